@@ -6,8 +6,8 @@ use std::error::Error;
 use std::io;
 
 pub trait WasmBinary: Sized {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self>;
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize>;
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self>;
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize>;
 
     /// convenience method
     fn error<E>(reason: E) -> io::Result<Self>
@@ -15,35 +15,158 @@ pub trait WasmBinary: Sized {
     {
         Err(io::Error::new(io::ErrorKind::InvalidData, reason))
     }
+
+    /// like `error`, but appends the reader's current byte offset to the message, so a
+    /// malformed instruction or section can be pointed to directly instead of just "somewhere"
+    fn error_offset<R: WasmRead, E>(reader: &R, reason: E) -> io::Result<Self>
+        where E: ::std::fmt::Display
+    {
+        Self::error(format!("{} at offset 0x{:x}", reason, reader.position()))
+    }
+
+    /// size of `self` once encoded, without actually writing it anywhere. Used to get an exact
+    /// LEB128 length prefix without buffering the encoded content in a `Vec<u8>` first.
+    ///
+    /// `CountingWriter` itself never fails, but `encode` can still return `Err` for a content
+    /// validation problem (e.g. `Leb128<usize>::encode` rejecting a value that doesn't fit in a
+    /// u32), so this has to propagate that `Err` rather than assume only I/O can fail here.
+    fn encoded_size(&self) -> io::Result<usize> {
+        let mut writer = CountingWriter::new();
+        self.encode(&mut writer)?;
+        Ok(writer.count())
+    }
+}
+
+/// A reader that additionally knows its current position in the overall byte stream, so decode
+/// errors can report where the malformed input was found.
+pub trait WasmRead: io::Read {
+    fn position(&self) -> u64;
+}
+
+/// A writer that additionally accepts a hint for how many more bytes are about to be written, so
+/// the underlying storage (e.g. a `Vec<u8>`) can reserve up front instead of growing piecemeal.
+pub trait WasmWrite: io::Write {
+    fn size_hint(&mut self, bytes: usize);
+
+    /// Whether `write_vectored` on this writer is worth using over separate `write_all` calls.
+    /// Defaults to false, since for something like a `Vec<u8>` a vectored write is just as many
+    /// copies as writing each slice separately; writers backed by a file or socket should
+    /// override this once they actually batch the underlying syscalls.
+    fn supports_vectored(&self) -> bool {
+        false
+    }
+}
+
+/// Wraps any reader to track how many bytes have been consumed from it so far.
+pub struct OffsetReader<R> {
+    inner: R,
+    // offset of `inner`'s first byte within the original file/stream, so that decoding from an
+    // already-sliced-out buffer (a section's payload, a function's body, ...) still reports
+    // `position()` relative to the file instead of relative to that buffer
+    base: u64,
+    pos: u64,
+}
+
+impl<R: io::Read> OffsetReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_base(inner, 0)
+    }
+
+    /// like `new`, but `position()` is reported as `base + bytes consumed so far`, for readers
+    /// that start mid-file (e.g. a section's or function's own buffer)
+    pub fn with_base(inner: R, base: u64) -> Self {
+        OffsetReader { inner, base, pos: 0 }
+    }
+}
+
+impl<R: io::Read> io::Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<R: io::Read> WasmRead for OffsetReader<R> {
+    fn position(&self) -> u64 {
+        self.base + self.pos
+    }
+}
+
+/// Every plain `io::Write` gets a no-op `size_hint` by default; `Vec<u8>` specializes it to an
+/// actual `reserve` call, since it's the only writer we currently buffer into.
+impl<W: io::Write> WasmWrite for W {
+    default fn size_hint(&mut self, _bytes: usize) {}
+}
+
+impl WasmWrite for Vec<u8> {
+    fn size_hint(&mut self, bytes: usize) {
+        self.reserve(bytes);
+    }
+}
+
+/// Encoding straight to a file is the actual target for vectored writes: batching an element's
+/// size prefix and content into one `write_vectored` call saves a syscall per element versus two
+/// separate `write_all` calls, which a simple in-memory `Vec<u8>` write never would.
+impl WasmWrite for ::std::fs::File {
+    fn supports_vectored(&self) -> bool {
+        true
+    }
+}
+
+/// A writer that discards all bytes written to it and just counts how many there were.
+pub struct CountingWriter {
+    count: usize,
+}
+
+impl CountingWriter {
+    pub fn new() -> Self {
+        CountingWriter { count: 0 }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl io::Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 
 /* Primitive types */
 
 impl WasmBinary for u8 {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         reader.read_u8()
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         writer.write_u8(*self)?;
         Ok(1)
     }
 }
 
 impl WasmBinary for Leb128<u32> {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         reader.read_leb128()
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         writer.write_leb128(self)
     }
 }
 
 impl WasmBinary for Leb128<usize> {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         reader.read_leb128()
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         if self.value > u32::max_value() as usize {
             Self::error("WASM spec does not allow unsigned larger than u32")?;
         }
@@ -52,38 +175,38 @@ impl WasmBinary for Leb128<usize> {
 }
 
 impl WasmBinary for Leb128<i32> {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         reader.read_leb128()
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         writer.write_leb128(self)
     }
 }
 
 impl WasmBinary for Leb128<i64> {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         reader.read_leb128()
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         writer.write_leb128(self)
     }
 }
 
 impl WasmBinary for f32 {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         reader.read_f32::<LittleEndian>()
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         writer.write_f32::<LittleEndian>(*self)?;
         Ok(4)
     }
 }
 
 impl WasmBinary for f64 {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         reader.read_f64::<LittleEndian>()
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         writer.write_f64::<LittleEndian>(*self)?;
         Ok(8)
     }
@@ -93,28 +216,27 @@ impl WasmBinary for f64 {
 /* Generic "AST combinators" */
 
 impl<T: WasmBinary> WasmBinary for WithSize<T> {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         Ok(WithSize {
             size: Leb128::<u32>::decode(reader)?.map(()),
             content: T::decode(reader)?,
         })
     }
 
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
-        let mut buf = Vec::new();
-        let new_size = self.content.encode(&mut buf)?;
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
+        // cheap counting pass to learn the exact size, so the content can be streamed directly
+        // to `writer` afterwards instead of being buffered in an intermediate `Vec<u8>`
+        let new_size = self.content.encoded_size()?;
 
-        // write new size, then contents from buffer to actual writer
         let mut bytes_written = self.size.map(new_size).encode(writer)?;
-        writer.write_all(&buf)?;
-        bytes_written += new_size;
+        bytes_written += self.content.encode(writer)?;
 
         Ok(bytes_written)
     }
 }
 
 impl<T: WasmBinary> WasmBinary for Leb128<Vec<T>> {
-    default fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    default fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         let size = Leb128::decode(reader)?;
 
         let mut vec: Vec<T> = Vec::with_capacity(size.value);
@@ -125,7 +247,7 @@ impl<T: WasmBinary> WasmBinary for Leb128<Vec<T>> {
         Ok(size.map(vec))
     }
 
-    default fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    default fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         let new_size = self.len();
 
         let mut bytes_written = self.map(new_size).encode(writer)?;
@@ -138,7 +260,7 @@ impl<T: WasmBinary> WasmBinary for Leb128<Vec<T>> {
 }
 
 impl WasmBinary for Leb128<String> {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         // reuse Vec<u8> implementation, and then consume buf so no re-allocation is necessary.
         let buf: Leb128<Vec<u8>> = Leb128::decode(reader)?;
         match String::from_utf8(buf.value) {
@@ -150,7 +272,7 @@ impl WasmBinary for Leb128<String> {
         }
     }
 
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         let new_size = self.len();
 
         let mut bytes_written = self.map(new_size).encode(writer)?;
@@ -165,24 +287,27 @@ impl WasmBinary for Leb128<String> {
 /// Uses trait specialization (https://github.com/rust-lang/rfcs/blob/master/text/1210-impl-specialization.md)
 /// to provide parallel decoding/encoding (right now only Code section has the necessary Vec<WithSize<T>> structure).
 impl<T: WasmBinary + Send + Sync> WasmBinary for Leb128<Vec<WithSize<T>>> {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         let num_elements = Leb128::decode(reader)?;
 
-        // read all elements into buffers of the given size (non-parallel, but hopefully fast)
+        // read all elements into buffers of the given size (non-parallel, but hopefully fast),
+        // remembering where each buffer starts in the file so errors decoded from it later can
+        // still report a file-relative offset instead of one relative to the buffer itself
         let mut bufs = Vec::new();
         for _ in 0..num_elements.value {
             let num_bytes = Leb128::decode(reader)?;
+            let base = reader.position();
             let mut buf = vec![0u8; num_bytes.value];
             reader.read_exact(&mut buf)?;
-            bufs.push(num_bytes.map(buf));
+            bufs.push((base, num_bytes.map(buf)));
         }
 
         // parallel decode of each buffer
         let decoded: io::Result<Vec<WithSize<T>>> = bufs.into_par_iter()
-            .map(|buf| {
+            .map(|(base, buf)| {
                 Ok(WithSize {
                     size: buf.map(()),
-                    content: T::decode(&mut &buf.value[..])?,
+                    content: T::decode(&mut OffsetReader::with_base(&buf.value[..], base))?,
                 })
             })
             .collect();
@@ -191,7 +316,7 @@ impl<T: WasmBinary + Send + Sync> WasmBinary for Leb128<Vec<WithSize<T>>> {
         Ok(num_elements.map(decoded))
     }
 
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         let new_size = self.map(self.len());
         let mut bytes_written = new_size.encode(writer)?;
 
@@ -206,52 +331,183 @@ impl<T: WasmBinary + Send + Sync> WasmBinary for Leb128<Vec<WithSize<T>>> {
                 })
             })
             .collect();
+        let encoded = encoded?;
 
-        // write sizes and buffer contents to actual writer (non-parallel, but hopefully fast)
-        for buf in encoded? {
-            let size = buf.size.map(buf.content.len());
-            bytes_written += size.encode(writer)?;
-            writer.write_all(&buf.content)?;
-            bytes_written += size.value;
+        // render every element's LEB128 size prefix into its own small scratch buffer up front,
+        // so the write loop below never has to re-encode a size to find out how many bytes it is
+        let prefixes: io::Result<Vec<Vec<u8>>> = encoded.iter()
+            .map(|buf| {
+                let mut prefix = Vec::new();
+                buf.size.map(buf.content.len()).encode(&mut prefix)?;
+                Ok(prefix)
+            })
+            .collect();
+        let prefixes = prefixes?;
+
+        // now that every element is encoded, hint the total output size to the writer (e.g. so a
+        // `Vec<u8>` can reserve its capacity in one go instead of growing as the loop below writes)
+        let total_size: usize = prefixes.iter().map(Vec::len).sum::<usize>()
+            + encoded.iter().map(|buf| buf.content.len()).sum::<usize>();
+        writer.size_hint(total_size);
+
+        // write each element's size prefix + content to the actual writer (non-parallel, but
+        // hopefully fast); use a single vectored write per element when the writer supports it,
+        // to avoid two small syscalls/copies where one would do
+        let vectored = writer.supports_vectored();
+        for (prefix, buf) in prefixes.iter().zip(encoded.iter()) {
+            if vectored {
+                write_vectored_all(writer, prefix, &buf.content)?;
+            } else {
+                writer.write_all(prefix)?;
+                writer.write_all(&buf.content)?;
+            }
+            bytes_written += prefix.len() + buf.content.len();
         }
 
         Ok(bytes_written)
     }
 }
 
+/// Writes `prefix` then `content` via a single `write_vectored` call, falling back to plain
+/// `write_all` calls for whatever is left over in the rare case of a partial vectored write.
+fn write_vectored_all<W: io::Write>(writer: &mut W, prefix: &[u8], content: &[u8]) -> io::Result<()> {
+    let slices = [io::IoSlice::new(prefix), io::IoSlice::new(content)];
+    let written = writer.write_vectored(&slices)?;
+
+    if written >= prefix.len() + content.len() {
+        return Ok(());
+    }
+    if written < prefix.len() {
+        writer.write_all(&prefix[written..])?;
+        writer.write_all(content)
+    } else {
+        writer.write_all(&content[written - prefix.len()..])
+    }
+}
+
 
 /* Special cases that cannot be derived and need a manual impl */
 
+/// the section id determines which variant to decode the payload into; sections whose id we
+/// don't structurally understand (custom sections like "name" or "producers", or future standard
+/// sections we haven't added yet) must still round-trip byte-for-byte. `ast::Section` has grown
+/// two variants for this: `Custom` keeps the name plus the remaining payload, `Raw` just keeps
+/// the payload verbatim. These are plain associated functions (not a `WasmBinary` impl) because
+/// `Module` reads the id/size framing itself so it can slurp every section's bytes up front and
+/// decode the payloads in parallel; a section never owns its own length prefix.
+///
+/// Every section id goes through the same id + LEB128 byte-size framing in `Module::decode`/
+/// `encode` below, not just `Custom`/`Raw` -- `Type`/`Function`/`Code` are handed a `payload`
+/// slice that is already exactly that many bytes, same as every other id.
+impl Section {
+    /// `base` is this section's payload's starting offset in the original file, so that a decode
+    /// error found deep inside (e.g. a malformed instruction) reports a file-relative offset
+    /// instead of one relative to the already-sliced-out `payload` buffer.
+    fn decode_content(id: u8, payload: Vec<u8>, base: u64) -> io::Result<Self> {
+        Ok(match id {
+            0 => {
+                let mut remaining = OffsetReader::with_base(&payload[..], base);
+                let name = Leb128::<String>::decode(&mut remaining)?;
+                let consumed = ((remaining.position() - base) as usize).min(payload.len());
+                Section::Custom { name, bytes: payload[consumed..].to_vec() }
+            }
+            1 => Section::Type(Leb128::decode(&mut OffsetReader::with_base(&payload[..], base))?),
+            3 => Section::Function(Leb128::decode(&mut OffsetReader::with_base(&payload[..], base))?),
+            10 => Section::Code(Leb128::decode(&mut OffsetReader::with_base(&payload[..], base))?),
+            id => Section::Raw { id, bytes: payload },
+        })
+    }
+
+    /// encodes just the section's payload (no id/size framing) into `buf`, returning its id
+    fn encode_content(&self, buf: &mut Vec<u8>) -> io::Result<u8> {
+        match *self {
+            Section::Custom { ref name, ref bytes } => {
+                name.encode(buf)?;
+                buf.extend_from_slice(bytes);
+                Ok(0)
+            }
+            Section::Type(ref types) => {
+                types.encode(buf)?;
+                Ok(1)
+            }
+            Section::Function(ref funcs) => {
+                funcs.encode(buf)?;
+                Ok(3)
+            }
+            Section::Code(ref code) => {
+                code.encode(buf)?;
+                Ok(10)
+            }
+            Section::Raw { id, ref bytes } => {
+                buf.extend_from_slice(bytes);
+                Ok(id)
+            }
+        }
+    }
+}
+
 impl WasmBinary for Module {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         let mut magic_number = [0u8; 4];
         reader.read_exact(&mut magic_number)?;
         if &magic_number != b"\0asm" {
-            return Self::error("magic bytes do not match");
+            return Self::error_offset(reader, "magic bytes do not match");
         }
 
         let version = reader.read_u32::<LittleEndian>()?;
         if version != 1 {
-            return Self::error("not version 1");
+            return Self::error_offset(reader, "not version 1");
         }
 
-        let mut sections = Vec::new();
+        // cheap, sequential I/O: read each section's id + length-delimited payload into a buffer,
+        // jumping straight to the next section instead of structurally decoding along the way.
+        // Remember each payload's starting offset in the file too, so a decode error found deep
+        // inside it (by the parallel pass below) still reports a file-relative byte offset.
+        let mut chunks = Vec::new();
         loop {
-            match Section::decode(reader) {
-                Ok(section) => sections.push(section),
+            let id = match u8::decode(reader) {
+                Ok(id) => id,
                 Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e)
+                Err(e) => return Err(e),
             };
+            let size = Leb128::<u32>::decode(reader)?;
+            let base = reader.position();
+
+            // `size` comes straight off the wire and isn't checked against what's actually left
+            // to read, so don't pre-allocate a zeroed buffer of that length up front -- a
+            // truncated/corrupt file could claim close to 4GiB for a single section and abort the
+            // process on the allocation before `read_exact` ever got a chance to fail cleanly.
+            // Reading through a `Take` only ever grows `payload` as bytes actually arrive.
+            let mut payload = Vec::new();
+            (&mut *reader).take(size.value as u64).read_to_end(&mut payload)?;
+            if payload.len() != size.value as usize {
+                return Self::error_offset(reader, format!(
+                    "section claims {} byte(s) but only {} were available", size.value, payload.len()));
+            }
+
+            chunks.push((id, payload, base));
         }
 
-        Ok(Module { version, sections })
+        // CPU-heavy work: decode each section's payload concurrently, in any order, then collect
+        // back in the original section order
+        let sections: io::Result<Vec<Section>> = chunks.into_par_iter()
+            .map(|(id, payload, base)| Section::decode_content(id, payload, base))
+            .collect();
+
+        Ok(Module { version, sections: sections? })
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         writer.write_all(b"\0asm")?;
         writer.write_all(&[1, 0, 0, 0])?;
         let mut bytes_written = 8;
         for section in &self.sections {
-            bytes_written += section.encode(writer)?;
+            let mut payload = Vec::new();
+            let id = section.encode_content(&mut payload)?;
+
+            bytes_written += id.encode(writer)?;
+            bytes_written += Leb128 { value: payload.len() as u32, byte_count: 0 }.encode(writer)?;
+            writer.write_all(&payload)?;
+            bytes_written += payload.len();
         }
         Ok(bytes_written)
     }
@@ -259,7 +515,7 @@ impl WasmBinary for Module {
 
 /// needs manual impl because of Else/End handling
 impl WasmBinary for Expr {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         let mut instructions = Vec::new();
 
         let mut found_end = false;
@@ -276,7 +532,7 @@ impl WasmBinary for Expr {
 
         Ok(Expr(instructions))
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         let mut bytes_written = 0;
         for instruction in &self.0 {
             bytes_written += instruction.encode(writer)?;
@@ -288,19 +544,125 @@ impl WasmBinary for Expr {
 /// needs manual impl because of compressed format: even though it is "logically" an enum, it has
 /// no tag, because they know that 0x40 and ValType are disjoint
 impl WasmBinary for BlockType {
-    fn decode<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+    fn decode<R: WasmRead>(reader: &mut R) -> io::Result<Self> {
         Ok(BlockType(match u8::decode(reader)? {
             0x40 => None,
             byte => {
                 let mut buf = [byte; 1];
-                Some(ValType::decode(&mut &buf[..])?)
+                Some(ValType::decode(&mut OffsetReader::new(&buf[..]))?)
             }
         }))
     }
-    fn encode<W: io::Write>(&self, writer: &mut W) -> io::Result<usize> {
+    fn encode<W: WasmWrite>(&self, writer: &mut W) -> io::Result<usize> {
         match self {
             &BlockType(None) => 0x40u8.encode(writer),
             &BlockType(Some(ref val_type)) => val_type.encode(writer)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// encodes `section`'s content, then decodes it back, the same way `Module` does for every
+    /// section of every id
+    fn round_trip_content(section: &Section) -> Section {
+        let mut buf = Vec::new();
+        let id = section.encode_content(&mut buf)
+            .expect("encode_content should not fail for a freshly constructed section");
+        Section::decode_content(id, buf, 0)
+            .expect("decode_content should recover exactly what encode_content just wrote")
+    }
+
+    #[test]
+    fn raw_section_round_trips_byte_for_byte() {
+        let original = Section::Raw { id: 42, bytes: vec![0xde, 0xad, 0xbe, 0xef] };
+
+        match round_trip_content(&original) {
+            Section::Raw { id, bytes } => {
+                assert_eq!(id, 42);
+                assert_eq!(bytes, vec![0xde, 0xad, 0xbe, 0xef]);
+            }
+            _ => panic!("expected Section::Raw to round-trip back into a Section::Raw"),
+        }
+    }
+
+    #[test]
+    fn custom_section_round_trips_name_and_bytes() {
+        let original = Section::Custom {
+            name: Leb128 { value: "name".to_string(), byte_count: 0 },
+            bytes: vec![1, 2, 3, 4],
+        };
+
+        match round_trip_content(&original) {
+            Section::Custom { name, bytes } => {
+                assert_eq!(name.value, "name");
+                assert_eq!(bytes, vec![1, 2, 3, 4]);
+            }
+            _ => panic!("expected Section::Custom to round-trip back into a Section::Custom"),
+        }
+    }
+
+    /// A `Write` that only ever accepts `cap` bytes per call (vectored or not), to exercise
+    /// `write_vectored_all`'s fallback for a short `write_vectored` write.
+    struct ShortWriter {
+        written: Vec<u8>,
+        cap: usize,
+    }
+
+    impl io::Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.cap);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+            let mut remaining = self.cap;
+            let mut total = 0;
+            for buf in bufs {
+                if remaining == 0 {
+                    break;
+                }
+                let n = buf.len().min(remaining);
+                self.written.extend_from_slice(&buf[..n]);
+                remaining -= n;
+                total += n;
+            }
+            Ok(total)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_vectored_all_finishes_a_write_that_stops_mid_content() {
+        let prefix = vec![1, 2, 3];
+        let content = vec![4, 5, 6, 7, 8];
+        let mut writer = ShortWriter { written: Vec::new(), cap: 4 };
+
+        write_vectored_all(&mut writer, &prefix, &content)
+            .expect("should finish writing despite the short vectored write");
+
+        let mut expected = prefix;
+        expected.extend_from_slice(&content);
+        assert_eq!(writer.written, expected);
+    }
+
+    #[test]
+    fn write_vectored_all_finishes_a_write_that_stops_mid_prefix() {
+        let prefix = vec![1, 2, 3];
+        let content = vec![4, 5, 6, 7, 8];
+        let mut writer = ShortWriter { written: Vec::new(), cap: 2 };
+
+        write_vectored_all(&mut writer, &prefix, &content)
+            .expect("should finish writing despite the short vectored write");
+
+        let mut expected = prefix;
+        expected.extend_from_slice(&content);
+        assert_eq!(writer.written, expected);
+    }
+}